@@ -1,89 +1,248 @@
 use server::Server;
-use std::io::{Read, Write, Result};
+use protocol::{Protocol, ConnectionHandle, FrameMode};
+use std::io::{Read, Write, Result, Error, ErrorKind};
 use std::vec::Vec;
-use mio::{EventLoop, Token, EventSet, PollOpt};
+use std::collections::VecDeque;
+use std::mem;
+use mio::{EventLoop, Token, EventSet, PollOpt, Timeout};
 use mio::tcp::TcpStream;
 
+// a length-prefixed frame is a 4-byte big-endian length header followed by that many bytes
+// of payload
+const FRAME_HEADER_LEN: usize = 4;
+
+// pops every complete length-prefixed frame (header fully present AND payload fully present)
+// off the front of buffer and returns their payloads, in order. A trailing partial frame
+// (header not fully arrived, or payload still incomplete) is left in buffer untouched, ready
+// for the next call once more bytes have arrived. If a header claims a payload larger than
+// max_frame_size, this returns an error without draining that frame, so a connection can be
+// closed rather than letting receive_buffer grow without bound for the life of the connection.
+// Complete frames ahead of it that this call already popped are dropped along with the error.
+// Kept as a free function, independent of Connection and the socket, so the framing math can
+// be exercised directly with plain Vec<u8> buffers in tests.
+fn extract_frames(buffer: &mut Vec<u8>, max_frame_size: usize) -> Result<Vec<Vec<u8>>> {
+    let mut frames = Vec::new();
+
+    loop {
+        if buffer.len() < FRAME_HEADER_LEN {
+            break;
+        }
+
+        let payload_len = ((buffer[0] as usize) << 24)
+            | ((buffer[1] as usize) << 16)
+            | ((buffer[2] as usize) << 8)
+            | (buffer[3] as usize);
+
+        if payload_len > max_frame_size {
+            return Err(Error::new(ErrorKind::Other, "frame payload exceeds max_frame_size"));
+        }
+
+        let frame_len = FRAME_HEADER_LEN + payload_len;
+
+        if buffer.len() < frame_len {
+            break;
+        }
+
+        let frame: Vec<u8> = buffer.drain(0..frame_len).skip(FRAME_HEADER_LEN).collect();
+        frames.push(frame);
+    }
+
+    Ok(frames)
+}
+
 // this struct represents a connection from the outsite.
 // This is a pretty simple description by holding the socket
 // the token, and a list of interests (events we want to receive).
 // additionally we hold an queue of things to send, that we can work
-// through every time we can write
+// through every time we can write. The queue is a VecDeque so messages
+// leave in the order they were queued, and send_offset tracks how many
+// bytes of the front message have already been flushed, so a partial
+// write never loses data.
+// the protocol field holds the connection's own Protocol instance, which decides what to
+// actually do with the bytes we read. It's an Option so we can briefly take it out of self
+// while it's running, to hand it a ConnectionHandle that borrows the rest of the connection.
+// receive_buffer is persistent across readable() calls (instead of a throwaway allocation per
+// call), so a partial frame left over from one readiness event is still there on the next one.
+// pending_broadcasts collects messages a protocol asked to be fanned out to every other
+// connection through ConnectionHandle::broadcast. A Connection has no way to reach its
+// siblings itself (it doesn't know about the Slab they live in), so readable() hands these
+// back up to Server, which does.
+// idle_timeout_ms/timeout_handle implement the idle-connection deadline: a connection that
+// goes quiet for idle_timeout_ms gets closed by Server::timeout, so a silent client can't
+// hold a Slab slot forever.
+// max_frame_size bounds how large a single LengthPrefixed payload is allowed to claim to be:
+// without it, a peer could send a header claiming an enormous length and then trickle bytes
+// in slowly, forcing receive_buffer to grow without limit for the life of the connection
 pub struct Connection {
     socket: TcpStream,
     token: Token,
     interest: EventSet,
-    send_queue: Vec<Vec<u8>>,
+    send_queue: VecDeque<Vec<u8>>,
+    send_offset: usize,
+    protocol: Option<Box<Protocol>>,
+    frame_mode: FrameMode,
+    receive_buffer: Vec<u8>,
+    pending_broadcasts: Vec<Vec<u8>>,
+    idle_timeout_ms: u64,
+    timeout_handle: Option<Timeout>,
+    max_frame_size: usize,
 }
 
 // then we implement the methods for a connection
 impl Connection {
     // first we need a constructor, that creates everything we need
-    pub fn new(socket: TcpStream, token: Token) -> Connection {
+    pub fn new(socket: TcpStream, token: Token, protocol: Box<Protocol>, idle_timeout_ms: u64, max_frame_size: usize) -> Connection {
+        // ask the protocol once, up front, how it wants its data framed
+        let frame_mode = protocol.frame_mode();
+
         Connection {
             socket: socket,
             token: token,
             // we need a default eventset, and the simplest is to listen for
             // hung up sockets
             interest: EventSet::hup(),
-            send_queue: Vec::new(),
+            send_queue: VecDeque::new(),
+            send_offset: 0,
+            protocol: Some(protocol),
+            frame_mode: frame_mode,
+            receive_buffer: Vec::new(),
+            pending_broadcasts: Vec::new(),
+            idle_timeout_ms: idle_timeout_ms,
+            timeout_handle: None,
+            max_frame_size: max_frame_size,
         }
     }
 
-    // this method is called every time the socket is readable
-    pub fn readable(&mut self) -> Result<()> {
-        // so first we init a buffer list. The name is not that good, but it's the
-        // buffer where the complete received data gets written to. So it's a bytearray
-        // of ALL received bytes
-        let mut buffer_list: Vec<u8> = Vec::new();
+    // (re-)arms the idle deadline, cancelling whatever timeout was previously pending so we
+    // never leak a timer slot. Called on register (to start the clock) and again every time
+    // readable/writable makes progress (to push the deadline back out)
+    fn arm_timeout(&mut self, event_loop: &mut EventLoop<Server>) -> Result<()> {
+        if let Some(handle) = self.timeout_handle.take() {
+            event_loop.clear_timeout(handle);
+        }
+
+        match event_loop.timeout_ms(self.token, self.idle_timeout_ms) {
+            Ok(handle) => {
+                self.timeout_handle = Some(handle);
+                Ok(())
+            },
+            Err(_) => Err(Error::new(ErrorKind::Other, "failed to arm idle timeout")),
+        }
+    }
+
+    // cancels the pending idle deadline, if any. Must be called while closing a connection,
+    // or a stale timeout could fire later against a reused token
+    fn cancel_timeout(&mut self, event_loop: &mut EventLoop<Server>) {
+        if let Some(handle) = self.timeout_handle.take() {
+            event_loop.clear_timeout(handle);
+        }
+    }
+
+    // queues a message to be broadcast to every other live connection. Called by
+    // ConnectionHandle on behalf of a protocol; picked up and actually delivered by
+    // Server once readable() returns
+    pub fn queue_broadcast(&mut self, message: Vec<u8>) {
+        self.pending_broadcasts.push(message);
+    }
+
+    // called once, right after this connection has been registered with the event loop. Hands
+    // the protocol a chance to greet the peer or set up some state
+    pub fn notify_connected(&mut self) -> Result<()> {
+        if let Some(mut protocol) = self.protocol.take() {
+            {
+                let mut handle = ConnectionHandle::new(self);
+                protocol.on_connect(&mut handle);
+            }
+            self.protocol = Some(protocol);
+        }
+
+        Ok(())
+    }
+
+    // this method is called every time the socket is readable. It returns any messages this
+    // connection's protocol asked to be broadcast to every other connection, so Server (which
+    // is the one that actually owns all the connections) can deliver them
+    pub fn readable(&mut self, event_loop: &mut EventLoop<Server>) -> Result<Vec<Vec<u8>>> {
         // then we create a temporary buffer, that is 1kb long (1024 bytes), and that gets
         // used to receive the data
         let mut tmp_buffer: [u8; 1024] = [0; 1024];
 
-        // then we loop endlessly through the read, because we need to read ALL data. The
-        // eventloop is used in OneShot mode, so it'll notify us only once. If we don't read
-        // everything, this will kill the socket
+        // then we loop endlessly through the read, because we're edge triggered and oneshot,
+        // so we have to drain the socket completely or we'll never get notified again.
+        // We only stop once the kernel tells us there is nothing left (WouldBlock) or the
+        // peer closed the connection (a read of 0 bytes)
         loop {
-            // so basically we have to read the data into the tmp_buffer
             match self.socket.read(&mut tmp_buffer) {
-                // and if reading was successful
+                // a read of 0 bytes means the peer closed the connection, so tell the
+                // outer world to close this connection
+                Ok(0) => {
+                    return Err(Error::new(ErrorKind::Other, "connection closed by peer"));
+                },
+                // and if reading was successful, we append whatever we got to the receive_buffer
                 Ok(len) => {
-                    // we go for every byte and push it to the buffer_list for the final result
-                    for i in 0..len {
-                        buffer_list.push(tmp_buffer[i]);
-                    }
-
-                    // then, if we received less than a complete buffer, we assume there is nothing
-                    // left to read.
-                    if len < 1024 {
-                        break;
-                    }
+                    self.receive_buffer.extend_from_slice(&tmp_buffer[0..len]);
+                },
+                // WouldBlock simply means there is nothing left to read right now, so we're done
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+                    break;
                 },
-                // else there was an error, so we simply return the error
+                // else there was a real error, so we simply return the error
                 Err(e) => {
                     return Err(e);
                 }
             }
         }
 
-        // then, just for debugging, we convert the received buffer to an utf8 string
-        let buffer_as_string = String::from_utf8(buffer_list.clone()).ok().expect("Received buffer could not be parsed");
-        // and print it, so we know something happend
-        println!("Received: {}", buffer_as_string);
+        // now split whatever we have accumulated into messages, depending on how this
+        // connection's protocol wants its data framed
+        match self.frame_mode {
+            // raw mode has no notion of message boundaries: hand over everything we've got
+            FrameMode::Raw => {
+                if !self.receive_buffer.is_empty() {
+                    let bytes = mem::replace(&mut self.receive_buffer, Vec::new());
+                    self.dispatch(&bytes);
+                }
+            },
+            // length-prefixed mode: pop every complete frame (header fully present AND
+            // payload fully present) and dispatch it, leaving any partial frame in the
+            // buffer for the next readable() call
+            FrameMode::LengthPrefixed => {
+                let frames = try!(extract_frames(&mut self.receive_buffer, self.max_frame_size));
 
-        // because we echo everything, we simply send the received buffer back (just try it, it's not important currently)
-        try!(self.send_message(buffer_list));
+                for frame in &frames {
+                    self.dispatch(frame);
+                }
+            },
+        }
 
-        // and if we reach this, everything went smoothly, so tell the outer world: Ok
-        Ok(())
+        // we made progress, so push the idle deadline back out
+        try!(self.arm_timeout(event_loop));
+
+        // and if we reach this, everything went smoothly, so hand back whatever the protocol
+        // asked to have broadcast while it was processing the data above
+        Ok(mem::replace(&mut self.pending_broadcasts, Vec::new()))
     }
 
-    // this method gets called every time the socket is writeable. Every time it's writeable, we look
-    // into the send_queue and send the next item to send
-    pub fn writable(&mut self) -> Result<()> {
-        let mut buffer = match self.send_queue.pop() {
-            // if there is buffer to send, simply take it
-            Some(buffer) => buffer,
+    // hands a complete message over to this connection's protocol. We briefly take the
+    // protocol out of self so we can lend self to the ConnectionHandle at the same time
+    fn dispatch(&mut self, bytes: &[u8]) {
+        if let Some(mut protocol) = self.protocol.take() {
+            {
+                let mut handle = ConnectionHandle::new(self);
+                protocol.on_data(&mut handle, bytes);
+            }
+            self.protocol = Some(protocol);
+        }
+    }
+
+    // this method gets called every time the socket is writeable. Every time it's writeable, we
+    // try a single write of whatever is at the front of the send_queue, starting at send_offset.
+    // A non-blocking edge/oneshot socket can accept less than we hand it, so we have to remember
+    // how far we got instead of assuming the whole buffer went out
+    pub fn writable(&mut self, event_loop: &mut EventLoop<Server>) -> Result<()> {
+        let write_result = match self.send_queue.front() {
+            // if there is something to send, try to write the part we haven't sent yet
+            Some(buffer) => self.socket.write(&buffer[self.send_offset..]),
             // else there is nothing to send, so just return ok.
             // actually, we might treat this as error as well, because this can
             // never happen (we have only an interest in writing if send_message
@@ -93,10 +252,24 @@ impl Connection {
             }
         };
 
-        // then we try to send the buffer
-        match self.socket.write_all(&mut buffer) {
-            // and if it was ok
-            Ok(_) => {},
+        match write_result {
+            // we sent some bytes, so advance the offset
+            Ok(written) => {
+                self.send_offset += written;
+
+                // and if that completed the buffer at the front, drop it and reset the offset
+                // for the next one
+                if self.send_offset >= self.send_queue.front().unwrap().len() {
+                    self.send_queue.pop_front();
+                    self.send_offset = 0;
+                }
+            },
+            // the socket can't take any more right now. Leave the partially sent buffer at the
+            // front of the queue and keep our writable interest, so we pick up right where we
+            // left off on the next writable event
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+                return Ok(());
+            },
             Err(e) => {
                 // else there was an error with the socket, to tell the outer world
                 return Err(e);
@@ -109,13 +282,16 @@ impl Connection {
             self.interest.remove(EventSet::writable());
         }
 
+        // we made progress, so push the idle deadline back out
+        try!(self.arm_timeout(event_loop));
+
         // and finally simply return an Ok
         Ok(())
     }
 
     // queues things up for sending, and adds the interest to send
     pub fn send_message(&mut self, message: Vec<u8>) -> Result<()> {
-        self.send_queue.push(message);
+        self.send_queue.push_back(message);
         self.interest.insert(EventSet::writable());
         Ok(())
     }
@@ -125,12 +301,25 @@ impl Connection {
     pub fn register(&mut self, event_loop: &mut EventLoop<Server>) -> Result<()> {
         self.interest.insert(EventSet::readable());
 
-        event_loop.register(
+        try!(event_loop.register(
             &self.socket,
             self.token,
-            self.interest, 
+            self.interest,
             PollOpt::edge() | PollOpt::oneshot()
-        )
+        ));
+
+        // start the idle clock as soon as the connection is live. If this fails, the socket
+        // is already registered with mio even though we're about to report an error, so we
+        // have to deregister it again here: accept() treats any Err from register() as "this
+        // socket never made it into the eventloop" and just drops the Slab entry, which would
+        // otherwise leave a dangling mio registration that panics on the next readiness event
+        // for this token
+        if let Err(e) = self.arm_timeout(event_loop) {
+            let _ = event_loop.deregister(&self.socket);
+            return Err(e);
+        }
+
+        Ok(())
     }
 
     // and add a simple reregister
@@ -142,4 +331,103 @@ impl Connection {
             PollOpt::edge() | PollOpt::oneshot()
         )
     }
+
+    // called once the connection is being torn down, so the protocol gets a chance to clean up,
+    // then deregister the socket from the event loop
+    pub fn close(&mut self, event_loop: &mut EventLoop<Server>) -> Result<()> {
+        if let Some(mut protocol) = self.protocol.take() {
+            protocol.on_close();
+        }
+
+        // cancel the pending idle timeout so it can't fire later against a token that has
+        // since been handed to a different connection
+        self.cancel_timeout(event_loop);
+
+        event_loop.deregister(&self.socket)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::extract_frames;
+
+    // builds a length-prefixed frame (4-byte big-endian header + payload) as raw bytes
+    fn frame_bytes(payload: &[u8]) -> Vec<u8> {
+        let len = payload.len();
+        let mut bytes = vec![
+            ((len >> 24) & 0xFF) as u8,
+            ((len >> 16) & 0xFF) as u8,
+            ((len >> 8) & 0xFF) as u8,
+            (len & 0xFF) as u8,
+        ];
+        bytes.extend_from_slice(payload);
+        bytes
+    }
+
+    #[test]
+    fn leaves_partial_header_untouched() {
+        let mut buffer = vec![0, 0, 0];
+
+        let frames = extract_frames(&mut buffer, 1024).unwrap();
+
+        assert!(frames.is_empty());
+        assert_eq!(buffer, vec![0, 0, 0]);
+    }
+
+    #[test]
+    fn leaves_partial_payload_untouched() {
+        let mut buffer = frame_bytes(b"hello world");
+        buffer.truncate(buffer.len() - 1);
+        let original = buffer.clone();
+
+        let frames = extract_frames(&mut buffer, 1024).unwrap();
+
+        assert!(frames.is_empty());
+        assert_eq!(buffer, original);
+    }
+
+    #[test]
+    fn extracts_a_single_complete_frame() {
+        let mut buffer = frame_bytes(b"hello");
+
+        let frames = extract_frames(&mut buffer, 1024).unwrap();
+
+        assert_eq!(frames, vec![b"hello".to_vec()]);
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn extracts_a_zero_length_frame() {
+        let mut buffer = frame_bytes(b"");
+
+        let frames = extract_frames(&mut buffer, 1024).unwrap();
+
+        assert_eq!(frames, vec![Vec::<u8>::new()]);
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn extracts_multiple_frames_from_one_read_and_keeps_the_trailing_partial_one() {
+        let mut buffer = frame_bytes(b"one");
+        buffer.extend(frame_bytes(b"two"));
+        let mut partial_next = frame_bytes(b"three");
+        partial_next.truncate(partial_next.len() - 2);
+        buffer.extend(partial_next.clone());
+
+        let frames = extract_frames(&mut buffer, 1024).unwrap();
+
+        assert_eq!(frames, vec![b"one".to_vec(), b"two".to_vec()]);
+        assert_eq!(buffer, partial_next);
+    }
+
+    #[test]
+    fn rejects_a_frame_over_the_max_size_without_draining_it() {
+        let mut buffer = frame_bytes(b"hello");
+        let original = buffer.clone();
+
+        let result = extract_frames(&mut buffer, 4);
+
+        assert!(result.is_err());
+        assert_eq!(buffer, original);
+    }
 }
\ No newline at end of file
@@ -0,0 +1,10 @@
+extern crate mio;
+
+pub mod connection;
+pub mod protocol;
+pub mod server;
+
+// re-export the pieces a downstream crate needs to implement its own Protocol and run a
+// Server, so depending on this crate doesn't require reaching into its module layout
+pub use protocol::{Protocol, ConnectionHandle, FrameMode};
+pub use server::{Server, ServerMsg};
@@ -1,20 +1,46 @@
 extern crate mio;
+extern crate rust_tcp_server;
 
-mod connection;
-mod server;
-
-use server::Server;
+use rust_tcp_server::{Server, ServerMsg, Protocol, ConnectionHandle};
 use std::str::FromStr;
+use std::thread;
 use mio::EventLoop;
 use mio::tcp::TcpListener;
 
+// close a connection that hasn't said anything for 5 minutes
+const IDLE_TIMEOUT_MS: u64 = 5 * 60 * 1000;
+
+// the echo protocol below uses Raw framing, so this only bounds LengthPrefixed protocols;
+// picked generously at 1 MiB so a legitimate large message still fits
+const MAX_FRAME_SIZE: usize = 1024 * 1024;
+
+// the echo server this crate started out as is now just a ~10-line Protocol implementation:
+// whatever bytes arrive get printed, then sent straight back to the peer
+struct EchoProtocol;
+
+impl Protocol for EchoProtocol {
+    fn on_data(&mut self, conn: &mut ConnectionHandle, bytes: &[u8]) {
+        let buffer_as_string = String::from_utf8(bytes.to_vec()).ok().expect("Received buffer could not be parsed");
+        println!("Received: {}", buffer_as_string);
+
+        conn.send_message(bytes.to_vec()).ok().expect("Failed to queue echo reply");
+    }
+}
+
 fn main() {
     let addr = FromStr::from_str("127.0.0.1:8888").ok().expect("Failed to parse host:port string");
     let listener = TcpListener::bind(&addr).ok().expect("Failed to bind address");
     let mut event_loop = EventLoop::new().ok().expect("Failed to create event loop");
 
-    let mut server = Server::new(listener);
+    let mut server = Server::new(listener, Box::new(|| Box::new(EchoProtocol) as Box<Protocol>), IDLE_TIMEOUT_MS, MAX_FRAME_SIZE);
     server.register(&mut event_loop).ok().expect("Failed to register server with event loop");
 
+    // grab the channel before handing the event loop over to run(), so other threads can
+    // push work into the reactor without being inside a ready/notify callback themselves
+    let sender = event_loop.channel();
+    thread::spawn(move || {
+        sender.send(ServerMsg::Broadcast(b"server is up\n".to_vec())).ok();
+    });
+
     event_loop.run(&mut server).ok().expect("Failed to start event loop");
 }
@@ -0,0 +1,62 @@
+use connection::Connection;
+use std::io::Result;
+
+// a ConnectionHandle is what a Protocol implementation gets to interact with the connection
+// it is running on. It only exposes what a protocol actually needs (sending data), and keeps
+// the rest of Connection's internals (socket, interest, send_queue, ...) hidden
+pub struct ConnectionHandle<'a> {
+    connection: &'a mut Connection,
+}
+
+impl<'a> ConnectionHandle<'a> {
+    pub fn new(connection: &'a mut Connection) -> ConnectionHandle<'a> {
+        ConnectionHandle {
+            connection: connection,
+        }
+    }
+
+    // queues up a message to be sent back out on this connection
+    pub fn send_message(&mut self, message: Vec<u8>) -> Result<()> {
+        self.connection.send_message(message)
+    }
+
+    // queues up a message to be delivered to every *other* live connection. A single
+    // connection has no way to reach its siblings on its own, so this just records the
+    // request; Server is the one that actually fans it out once on_data returns
+    pub fn broadcast(&mut self, message: Vec<u8>) {
+        self.connection.queue_broadcast(message);
+    }
+}
+
+// how a Connection should split its receive buffer into the chunks it hands to on_data.
+// Raw has no notion of message boundaries (everything read during one readiness event),
+// LengthPrefixed frames each message with a 4-byte big-endian length header
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum FrameMode {
+    Raw,
+    LengthPrefixed,
+}
+
+// a Protocol implements the actual behavior of a server: what to do when a connection is
+// opened, when data arrives on it, and when it's closed. Server holds a factory that creates
+// one Protocol instance per Connection, so the event loop plumbing in Connection/Server stays
+// completely unaware of what the bytes flowing through it actually mean. The echo behavior this
+// crate started out with is now just one possible Protocol implementation.
+pub trait Protocol {
+    // called once, right after a connection has been accepted and registered with the event loop
+    fn on_connect(&mut self, _conn: &mut ConnectionHandle) {}
+
+    // called every time a full message has arrived on the connection. What counts as "a full
+    // message" depends on frame_mode: in Raw mode this is everything drained from the socket
+    // during one readiness event, in LengthPrefixed mode it's one complete frame
+    fn on_data(&mut self, conn: &mut ConnectionHandle, bytes: &[u8]);
+
+    // called once, right before the connection is closed
+    fn on_close(&mut self) {}
+
+    // which framing this protocol wants its connections to use. Defaults to Raw, so existing
+    // protocols (like the echo example) keep working unchanged
+    fn frame_mode(&self) -> FrameMode {
+        FrameMode::Raw
+    }
+}
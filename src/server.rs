@@ -1,23 +1,45 @@
 use connection::Connection;
+use protocol::Protocol;
 use std::io;
+use std::collections::HashSet;
 use mio::{EventLoop, Token, EventSet, PollOpt, Handler};
 use mio::tcp::TcpListener;
 use mio::util::Slab;
 
+// messages application threads can push into the running event loop through the Sender
+// returned by EventLoop::channel(). This is the standard mio way of waking the reactor from
+// outside the ready/notify callbacks
+pub enum ServerMsg {
+    // deliver this data to every currently connected client
+    Broadcast(Vec<u8>),
+    // force-close a specific connection
+    Disconnect(Token),
+    // shut the whole event loop down
+    Shutdown,
+}
+
 // define a struct, that is our server. It contains of the socket we're listening at
-// the server token itself, and a Slab of connections
+// the server token itself, a Slab of connections, and a factory that creates a fresh
+// Protocol instance for every connection that gets accepted.
+// connection_tokens mirrors which tokens are currently live in the Slab. We need it
+// whenever we want to reach "every other connection" (broadcasting), since Slab doesn't
+// give us an easy way to iterate its tokens directly
 pub struct Server {
     socket: TcpListener,
     token: Token,
     connections: Slab<Connection>,
+    connection_tokens: HashSet<Token>,
+    protocol_factory: Box<Fn() -> Box<Protocol>>,
+    idle_timeout_ms: u64,
+    max_frame_size: usize,
 }
 
 // then we need to impmlement everything the Handler-trait of mio tells us to implement
 impl Handler for Server {
-    // first define this two types. I don't know what they are for, so simply make them
-    // empty. This is valid, and works
-    type Timeout = ();
-    type Message = ();
+    // Timeout carries the token of the connection whose idle deadline elapsed. Message
+    // carries whatever application threads push in through the event loop's channel
+    type Timeout = Token;
+    type Message = ServerMsg;
 
     // then we define the ready-method. This function gets called every time the eventloop
     // has something to do for us. The eventloop gets called with the corresponding token,
@@ -41,7 +63,7 @@ impl Handler for Server {
             // and reuse it later in the callback, but the borrow checker hits hard when doing that
             self.find_connection_by_token(token)
                 // tell it about the writeable event
-                .writable()
+                .writable(event_loop)
                 // and tell it to reregister
                 .and_then(|_| self.find_connection_by_token(token).reregister(event_loop))
                 // and if something goes wrong, we close the connection. Maybe handle the error?
@@ -59,26 +81,65 @@ impl Handler for Server {
             }
             // else we have a readable event from another socket
             else {
-                // so find the connection
-                self.find_connection_by_token(token)
-                    // tell it about the readable thing
-                    .readable()
-                    // and reregister it back to the eventloop
-                    .and_then(|_| self.find_connection_by_token(token).reregister(event_loop))
+                // tell the connection about the readable thing. It hands back any messages
+                // its protocol asked to be broadcast to every other connection
+                match self.find_connection_by_token(token).readable(event_loop) {
+                    Ok(broadcasts) => {
+                        // fan the broadcasts out to every other live connection before we
+                        // reregister this one, same as the original mio multi-echo example
+                        if !broadcasts.is_empty() {
+                            self.broadcast(event_loop, token, broadcasts);
+                        }
+
+                        // and reregister it back to the eventloop
+                        if self.find_connection_by_token(token).reregister(event_loop).is_err() {
+                            self.close_connection(event_loop, token);
+                        }
+                    },
                     // else, if something went wrong, close the connection. Maybe handle the error?
-                    .unwrap_or_else(|_| {
+                    Err(_) => {
                         self.close_connection(event_loop, token);
-                    });
+                    }
+                }
             }
         }
     }
+
+    // called whenever something sent a message through the event loop's channel. This is
+    // how application threads reach into the reactor without being inside a ready callback
+    fn notify(&mut self, event_loop: &mut EventLoop<Server>, msg: ServerMsg) {
+        match msg {
+            ServerMsg::Broadcast(data) => self.broadcast_all(event_loop, data),
+            // unlike every other caller of close_connection, the token here comes from
+            // arbitrary application-thread code, not from mio's own readiness dispatch, so
+            // it isn't guaranteed to name a live connection. The listener's own token is a
+            // separate case: close_connection treats that as "shut the server down", which
+            // isn't what a stray Disconnect should ever trigger, so we just ignore it
+            ServerMsg::Disconnect(token) => {
+                if token != self.token {
+                    self.close_connection(event_loop, token);
+                }
+            },
+            ServerMsg::Shutdown => event_loop.shutdown(),
+        }
+    }
+
+    // called once a connection's idle deadline elapses without any readable/writable
+    // activity to push it back out. Close the connection so a silent client can't hold a
+    // Slab slot forever
+    fn timeout(&mut self, event_loop: &mut EventLoop<Server>, token: Token) {
+        self.close_connection(event_loop, token);
+    }
 }
 // now we implemented the handler trait for the Server struct.
 // so now we need to implement the Server struct itself
 impl Server {
-    // first we create a new method, that only takes the TcpListener to use. Everything
-    // else is creatable on the fly
-    pub fn new(socket: TcpListener) -> Server {
+    // first we create a new method, that takes the TcpListener to use, a factory that creates
+    // a new Protocol instance for every connection we accept, how many milliseconds a
+    // connection may stay idle before it gets closed, and the largest payload a single
+    // LengthPrefixed frame is allowed to claim (ignored by connections using Raw framing).
+    // Everything else is creatable on the fly
+    pub fn new(socket: TcpListener, protocol_factory: Box<Fn() -> Box<Protocol>>, idle_timeout_ms: u64, max_frame_size: usize) -> Server {
         Server {
             socket: socket,
             // we start at Token(1) because some people wrote kqueue (an event system
@@ -87,6 +148,10 @@ impl Server {
             // and because we start the Token(1), we have to tell the Slab to start at
             // Token(2) and enable it to go up to 16384 (so 16382 connections)
             connections: Slab::new_starting_at(Token(2), 16384),
+            connection_tokens: HashSet::new(),
+            protocol_factory: protocol_factory,
+            idle_timeout_ms: idle_timeout_ms,
+            max_frame_size: max_frame_size,
         }
     }
 
@@ -145,19 +210,28 @@ impl Server {
         // now, after this long block, we have an actual socket. Now we can work
         // with the socket
 
+        // every connection gets its own, fresh Protocol instance, created through the factory
+        let protocol = (self.protocol_factory)();
+
         // so now we say the connections Slab, that we want to insert something to
         // get a place to do so
-        match self.connections.insert_with(|token| {
+        let idle_timeout_ms = self.idle_timeout_ms;
+        let max_frame_size = self.max_frame_size;
+
+        match self.connections.insert_with(move |token| {
             // and because we have a place for the socket, we create a connection
             // struct and return it
-            Connection::new(socket, token)
+            Connection::new(socket, token, protocol, idle_timeout_ms, max_frame_size)
         }) {
             // if the insert was successful
             Some(token) => {
                 // we register the token to the eventloop
                 match self.find_connection_by_token(token).register(event_loop) {
-                    // and do nothing else
-                    Ok(_) => {}
+                    // and tell the protocol the connection is up
+                    Ok(_) => {
+                        self.connection_tokens.insert(token);
+                        self.find_connection_by_token(token).notify_connected().unwrap_or(());
+                    }
                     // else there was an error. Maybe log the error?
                     Err(_) => {
                         // so we remove the socket again. It was never added to the
@@ -185,7 +259,7 @@ impl Server {
     fn close_connection(&mut self, event_loop: &mut EventLoop<Server>, token: Token) {
         if self.token == token {
             event_loop.shutdown();
-        } else {
+        } else if self.connections.contains(token) {
             match self.find_connection_by_token(token).close(event_loop) {
                 Ok(_) => {}
                 Err(e) => {
@@ -193,9 +267,60 @@ impl Server {
                 }
             };
             self.connections.remove(token);
+            self.connection_tokens.remove(&token);
             println!("Closed a connection, got {} connections left",
                      self.connections.count());
         }
+        // else: token doesn't name a live connection anymore. Every caller that gets its
+        // token from mio's own readiness dispatch can't hit this, but ServerMsg::Disconnect
+        // lets application threads supply an arbitrary token, so a stale one (the ordinary
+        // TOCTOU of the connection having already closed on its own) has to be a no-op
+        // instead of a Slab index panic
+    }
+
+    // delivers each of the given messages to every live connection except the one the data
+    // came from, then reregisters those peers so the event loop actually wakes us up for
+    // their new writable interest. This is the mio multi-echo fan-out pattern
+    fn broadcast(&mut self, event_loop: &mut EventLoop<Server>, origin: Token, messages: Vec<Vec<u8>>) {
+        let peer_tokens: Vec<Token> = self.connection_tokens
+            .iter()
+            .cloned()
+            .filter(|peer_token| *peer_token != origin)
+            .collect();
+
+        self.deliver_to(event_loop, peer_tokens, &messages);
+    }
+
+    // same as broadcast, but for a message that didn't originate from any connection (e.g. one
+    // pushed in from another thread via ServerMsg::Broadcast), so there's no origin to exclude
+    fn broadcast_all(&mut self, event_loop: &mut EventLoop<Server>, message: Vec<u8>) {
+        let peer_tokens: Vec<Token> = self.connection_tokens.iter().cloned().collect();
+
+        self.deliver_to(event_loop, peer_tokens, &vec![message]);
+    }
+
+    // queues every one of messages onto each connection in peer_tokens, then reregisters it
+    fn deliver_to(&mut self, event_loop: &mut EventLoop<Server>, peer_tokens: Vec<Token>, messages: &Vec<Vec<u8>>) {
+        // snapshotting peer_tokens up front (done by both callers) lets us iterate freely here
+        // while also mutating connections through the Slab
+        for peer_token in peer_tokens {
+            {
+                let peer = self.find_connection_by_token(peer_token);
+
+                for message in messages {
+                    // send_message can't fail in practice (it only queues and always returns Ok), so there's
+                    // nothing useful to do with an error here beyond letting the peer's own
+                    // readable/writable calls discover it later
+                    let _ = peer.send_message(message.clone());
+                }
+            }
+
+            // send_message only updated the peer's interest; reregister it so the eventloop
+            // learns about the new writable interest
+            if self.find_connection_by_token(peer_token).reregister(event_loop).is_err() {
+                self.close_connection(event_loop, peer_token);
+            }
+        }
     }
 
     // this function returns a lifetime mutable connection. To be honest, I don't fully get it,